@@ -1,9 +1,20 @@
-use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 
 const SOCKS_VERSION: u8 = 5;
+const SOCKS4_VERSION: u8 = 4;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
 
 #[derive(Debug)]
 enum SocksError {
@@ -12,6 +23,8 @@ enum SocksError {
     UnsupportedAuthMethod,
     UnsupportedCommand,
     UnsupportedAddressType,
+    AuthenticationFailed,
+    UpstreamError(String),
 }
 impl From<io::Error> for SocksError {
     fn from(error: io::Error) -> Self {
@@ -19,66 +32,164 @@ impl From<io::Error> for SocksError {
     }
 }
 
-fn handle_client(mut client: TcpStream) -> Result<(), SocksError> {
-    // Read the SOCKS version and number of authentication methods
-    let mut header = [0u8; 2];
-    client.read_exact(&mut header)?;
-    
-    let version = header[0];
-    let nmethods = header[1] as usize;
-    
-    if version != SOCKS_VERSION {
-        return Err(SocksError::UnsupportedVersion);
+impl std::fmt::Display for SocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocksError::IoError(e) => write!(f, "I/O error: {}", e),
+            SocksError::UnsupportedVersion => write!(f, "unsupported SOCKS version"),
+            SocksError::UnsupportedAuthMethod => write!(f, "no acceptable authentication method"),
+            SocksError::UnsupportedCommand => write!(f, "unsupported SOCKS command"),
+            SocksError::UnsupportedAddressType => write!(f, "unsupported address type"),
+            SocksError::AuthenticationFailed => write!(f, "authentication failed"),
+            SocksError::UpstreamError(msg) => write!(f, "upstream proxy error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {}
+
+/// Verifies a username/password pair during RFC 1929 sub-negotiation.
+///
+/// Implementations can back this with a static map, a database, or any
+/// other credential source; `handle_client` only depends on this trait.
+trait Authenticator: Send + Sync {
+    fn verify(&self, user: &str, pass: &str) -> bool;
+}
+
+/// An `Authenticator` backed by a fixed, in-memory username/password map.
+struct StaticCredentials {
+    users: HashMap<String, String>,
+}
+
+impl StaticCredentials {
+    fn new(users: HashMap<String, String>) -> Self {
+        StaticCredentials { users }
     }
-    
+}
+
+impl Authenticator for StaticCredentials {
+    fn verify(&self, user: &str, pass: &str) -> bool {
+        self.users.get(user).map(|p| p == pass).unwrap_or(false)
+    }
+}
+
+/// Configuration for chaining CONNECT requests through an upstream SOCKS5
+/// proxy (typically a local Tor daemon) instead of dialing the target
+/// directly.
+struct UpstreamConfig {
+    addr: SocketAddr,
+    /// When set, chain every CONNECT through the upstream, not just `.onion`
+    /// targets.
+    always_chain: bool,
+}
+
+/// Per-connection bandwidth accounting and idle-timeout settings for the
+/// CONNECT forwarding loop.
+#[derive(Clone)]
+struct TransferConfig {
+    /// How long a direction of the relay may sit idle before it's torn down.
+    idle_timeout: Duration,
+    /// Byte cap for a single connection's transfer in either direction.
+    max_bytes_per_connection: Option<u64>,
+    /// Byte cap shared across every connection the proxy is relaying.
+    global_cap: Option<u64>,
+    /// Running total counted against `global_cap`.
+    global_used: Arc<AtomicU64>,
+}
+
+/// Reads the version byte and dispatches to the SOCKS5 or SOCKS4/4a
+/// handshake accordingly, so both protocols can share the one listening
+/// port.
+async fn handle_client(
+    mut client: TcpStream,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    upstream: Option<Arc<UpstreamConfig>>,
+    transfer: TransferConfig,
+) -> Result<(), SocksError> {
+    let mut version = [0u8; 1];
+    client.read_exact(&mut version).await?;
+
+    match version[0] {
+        SOCKS_VERSION => handle_socks5_client(client, authenticator, upstream, transfer).await,
+        SOCKS4_VERSION if authenticator.is_some() => {
+            // SOCKS4/4a has no sub-negotiation for credentials, so it can't
+            // satisfy a configured username/password requirement; refuse it
+            // outright rather than silently relaying unauthenticated.
+            client.write_all(&[0x00, 0x5B, 0, 0, 0, 0, 0, 0]).await?;
+            Err(SocksError::AuthenticationFailed)
+        }
+        SOCKS4_VERSION => handle_socks4_client(client, upstream, transfer).await,
+        _ => Err(SocksError::UnsupportedVersion),
+    }
+}
+
+async fn handle_socks5_client(
+    mut client: TcpStream,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    upstream: Option<Arc<UpstreamConfig>>,
+    transfer: TransferConfig,
+) -> Result<(), SocksError> {
+    // Read the number of authentication methods (the version byte was
+    // already consumed by `handle_client`'s dispatch)
+    let mut nmethods_byte = [0u8; 1];
+    client.read_exact(&mut nmethods_byte).await?;
+    let nmethods = nmethods_byte[0] as usize;
+
     // Read authentication methods
     let mut methods = vec![0u8; nmethods];
-    client.read_exact(&mut methods)?;
-    
-    // We'll only support no authentication (0x00) for now
-    if !methods.contains(&0) {
-        // Respond with "no acceptable methods"
-        client.write_all(&[SOCKS_VERSION, 0xFF])?;
-        return Err(SocksError::UnsupportedAuthMethod);
-    }
-    
-    // Respond with "no authentication required"
-    client.write_all(&[SOCKS_VERSION, 0x00])?;
-    
+    client.read_exact(&mut methods).await?;
+
+    // Decide which method we're willing to use: user/pass if the server is
+    // configured with credentials, otherwise no-auth.
+    let selected_method = match &authenticator {
+        Some(_) if methods.contains(&METHOD_USER_PASS) => METHOD_USER_PASS,
+        None if methods.contains(&METHOD_NO_AUTH) => METHOD_NO_AUTH,
+        _ => {
+            client.write_all(&[SOCKS_VERSION, METHOD_NO_ACCEPTABLE]).await?;
+            return Err(SocksError::UnsupportedAuthMethod);
+        }
+    };
+
+    client.write_all(&[SOCKS_VERSION, selected_method]).await?;
+
+    if selected_method == METHOD_USER_PASS {
+        authenticate_user_pass(&mut client, authenticator.as_deref().unwrap()).await?;
+    }
+
     // Read the connection request
     let mut request = [0u8; 4];
-    client.read_exact(&mut request)?;
-    
+    client.read_exact(&mut request).await?;
+
     let command = request[1];
     let address_type = request[3];
-    
+
     // Parse the target address based on address_type
     let target_addr = match address_type {
         0x01 => { // IPv4
             let mut addr = [0u8; 4];
-            client.read_exact(&mut addr)?;
+            client.read_exact(&mut addr).await?;
             format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3])
         },
         0x03 => { // Domain name
             let mut len = [0u8; 1];
-            client.read_exact(&mut len)?;
+            client.read_exact(&mut len).await?;
             let mut domain = vec![0u8; len[0] as usize];
-            client.read_exact(&mut domain)?;
+            client.read_exact(&mut domain).await?;
             String::from_utf8_lossy(&domain).to_string()
         },
         0x04 => { // IPv6
             let mut addr = [0u8; 16];
-            client.read_exact(&mut addr)?;
+            client.read_exact(&mut addr).await?;
             // Convert IPv6 bytes to string representation
             format!("[{}]", (0..8).map(|i| format!("{:02x}{:02x}", addr[i*2], addr[i*2+1]))
                 .collect::<Vec<String>>().join(":"))
         },
         _ => return Err(SocksError::UnsupportedAddressType),
     };
-    
+
     // Read the port (2 bytes, big-endian)
     let mut port_bytes = [0u8; 2];
-    client.read_exact(&mut port_bytes)?;
+    client.read_exact(&mut port_bytes).await?;
     let port = u16::from_be_bytes(port_bytes);
 
     // Add request logging
@@ -92,42 +203,148 @@ fn handle_client(mut client: TcpStream) -> Result<(), SocksError> {
 
     // Now handle the command with the parsed address and port
     match command {
-        0x01 => handle_connect(&mut client, &target_addr, port), // CONNECT
-        0x02 => handle_bind(&mut client, &target_addr, port),    // BIND
-        0x03 => handle_udp(&mut client, &target_addr, port),     // UDP ASSOCIATE
-        _ => return Err(SocksError::UnsupportedCommand),
+        0x01 => handle_connect(client, &target_addr, port, upstream.as_deref(), &transfer).await, // CONNECT
+        0x02 => handle_bind(client, &target_addr, port).await,    // BIND
+        0x03 => handle_udp(client, &target_addr, port).await,     // UDP ASSOCIATE
+        _ => Err(SocksError::UnsupportedCommand),
     }
 }
 
-fn handle_connect(client: &mut TcpStream, target_addr: &str, port: u16) -> Result<(), SocksError> {
-    // Move existing connection logic here
-    match TcpStream::connect(format!("{}:{}", target_addr, port)) {
-        Ok(mut target) => {
+/// Runs the RFC 1929 username/password sub-negotiation and writes the
+/// status reply. Returns `Err(SocksError::AuthenticationFailed)` after
+/// sending the failure reply, so callers can drop the connection.
+async fn authenticate_user_pass(client: &mut TcpStream, authenticator: &dyn Authenticator) -> Result<(), SocksError> {
+    let mut ver = [0u8; 1];
+    client.read_exact(&mut ver).await?;
+    if ver[0] != 0x01 {
+        client.write_all(&[0x01, 0x01]).await?;
+        return Err(SocksError::AuthenticationFailed);
+    }
+
+    let mut ulen = [0u8; 1];
+    client.read_exact(&mut ulen).await?;
+    let mut username = vec![0u8; ulen[0] as usize];
+    client.read_exact(&mut username).await?;
+
+    let mut plen = [0u8; 1];
+    client.read_exact(&mut plen).await?;
+    let mut password = vec![0u8; plen[0] as usize];
+    client.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let password = String::from_utf8_lossy(&password);
+
+    if authenticator.verify(&username, &password) {
+        client.write_all(&[0x01, 0x00]).await?;
+        Ok(())
+    } else {
+        client.write_all(&[0x01, 0x01]).await?;
+        Err(SocksError::AuthenticationFailed)
+    }
+}
+
+/// Dials `target_addr:port` directly, or through the configured upstream
+/// proxy when it applies, without touching the client connection. Shared by
+/// the SOCKS5 and SOCKS4/4a CONNECT handlers.
+async fn dial_target(target_addr: &str, port: u16, upstream: Option<&UpstreamConfig>) -> Result<TcpStream, SocksError> {
+    // .onion names can't be resolved by the OS; chain through the upstream
+    // SOCKS5 proxy (e.g. Tor) whenever one is configured and either applies
+    // to every request or the target specifically needs it.
+    let should_chain = upstream.is_some_and(|cfg| cfg.always_chain || target_addr.ends_with(".onion"));
+
+    if should_chain {
+        connect_via_upstream(upstream.unwrap().addr, target_addr, port).await
+    } else {
+        TcpStream::connect((target_addr, port)).await.map_err(SocksError::from)
+    }
+}
+
+/// Copies from `src` to `dst` until EOF, an idle timeout, an error, or a
+/// configured byte cap is hit, tallying transferred bytes into `counter`
+/// (and the config's shared `global_used` total) as it goes.
+async fn copy_with_limits(mut src: impl AsyncRead + Unpin, mut dst: impl AsyncWrite + Unpin, cfg: &TransferConfig, counter: Arc<AtomicU64>) {
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let n = match tokio::time::timeout(cfg.idle_timeout, src.read(&mut buf)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(_)) => break,
+            Err(_) => break, // idle timeout elapsed
+        };
+        if dst.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+
+        let conn_total = counter.fetch_add(n as u64, Ordering::SeqCst) + n as u64;
+        let global_total = cfg.global_used.fetch_add(n as u64, Ordering::SeqCst) + n as u64;
+        let conn_over = cfg.max_bytes_per_connection.is_some_and(|cap| conn_total > cap);
+        let global_over = cfg.global_cap.is_some_and(|cap| global_total > cap);
+        if conn_over || global_over {
+            break;
+        }
+    }
+
+    dst.shutdown().await.ok();
+}
+
+/// Splices `client` and `target` together until both directions have ended,
+/// each on EOF, an error, `transfer`'s idle timeout or a byte cap. Returns
+/// `(bytes_up, bytes_down)`.
+async fn relay(client: TcpStream, target: TcpStream, transfer: &TransferConfig) -> Result<(u64, u64), SocksError> {
+    let (client_r, client_w) = client.into_split();
+    let (target_r, target_w) = target.into_split();
+
+    let up_bytes = Arc::new(AtomicU64::new(0));
+    let down_bytes = Arc::new(AtomicU64::new(0));
+
+    let up_counter = up_bytes.clone();
+    let up_transfer = transfer.clone();
+    let client_to_target = tokio::spawn(async move {
+        copy_with_limits(client_r, target_w, &up_transfer, up_counter).await;
+    });
+
+    let down_counter = down_bytes.clone();
+    let down_transfer = transfer.clone();
+    let target_to_client = tokio::spawn(async move {
+        copy_with_limits(target_r, client_w, &down_transfer, down_counter).await;
+    });
+
+    client_to_target.await.ok();
+    target_to_client.await.ok();
+
+    Ok((up_bytes.load(Ordering::SeqCst), down_bytes.load(Ordering::SeqCst)))
+}
+
+/// Logs the "CONNECT target:port — up X bytes, down Y bytes, duration Z"
+/// summary line once a relayed connection closes.
+fn log_transfer_summary(cmd: &str, target_addr: &str, port: u16, transferred: &Result<(u64, u64), SocksError>, duration: Duration) {
+    if let Ok((up, down)) = transferred {
+        println!("{} {}:{} — up {} bytes, down {} bytes, duration {:.3}s", cmd, target_addr, port, up, down, duration.as_secs_f64());
+    }
+}
+
+async fn handle_connect(
+    mut client: TcpStream,
+    target_addr: &str,
+    port: u16,
+    upstream: Option<&UpstreamConfig>,
+    transfer: &TransferConfig,
+) -> Result<(), SocksError> {
+    match dial_target(target_addr, port, upstream).await {
+        Ok(target) => {
             // Send success response
             let response = [
                 SOCKS_VERSION, 0x00, 0x00, 0x01,
                 0, 0, 0, 0, // Bind address (localhost)
                 (port >> 8) as u8, port as u8, // Bind port
             ];
-            client.write_all(&response)?;
-            
-            // Start bidirectional forwarding
-            let mut target_clone = target.try_clone()?;
-            let mut client_clone1 = client.try_clone()?;
-            let mut client_clone2 = client.try_clone()?;
-
-            let client_to_target = thread::spawn(move || {
-                io::copy(&mut client_clone1, &mut target).ok();
-            });
-            
-            let target_to_client = thread::spawn(move || {
-                io::copy(&mut target_clone, &mut client_clone2).ok();
-            });
-            
-            client_to_target.join().unwrap();
-            target_to_client.join().unwrap();
-            
-            Ok(())
+            client.write_all(&response).await?;
+
+            let started = Instant::now();
+            let transferred = relay(client, target, transfer).await;
+            log_transfer_summary("CONNECT", target_addr, port, &transferred, started.elapsed());
+            transferred.map(|_| ())
         },
         Err(e) => {
             // Send failure response
@@ -136,72 +353,390 @@ fn handle_connect(client: &mut TcpStream, target_addr: &str, port: u16) -> Resul
                 0, 0, 0, 0, // Bind address
                 0, 0, // Bind port
             ];
-            client.write_all(&response)?;
-            Err(SocksError::IoError(e))
+            client.write_all(&response).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Handles a SOCKS4/4a connection after the version byte (`0x04`) has
+/// already been consumed by `handle_client`'s dispatch.
+async fn handle_socks4_client(mut client: TcpStream, upstream: Option<Arc<UpstreamConfig>>, transfer: TransferConfig) -> Result<(), SocksError> {
+    // CD(1) + DSTPORT(2) + DSTIP(4)
+    let mut header = [0u8; 7];
+    client.read_exact(&mut header).await?;
+    let command = header[0];
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = &header[3..7];
+
+    // USERID, NUL-terminated; we don't use it but must still consume it.
+    read_cstring(&mut client).await?;
+
+    // SOCKS4a: DSTIP is 0.0.0.x (x != 0) and a NUL-terminated hostname
+    // follows in place of a real IP.
+    let is_socks4a = ip[0] == 0 && ip[1] == 0 && ip[2] == 0 && ip[3] != 0;
+    let target_addr = if is_socks4a {
+        read_cstring(&mut client).await?
+    } else {
+        format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+    };
+
+    println!("New request: SOCKS4 CONNECT {}:{}", target_addr, port);
+
+    match command {
+        0x01 => handle_connect_socks4(client, &target_addr, port, upstream.as_deref(), &transfer).await,
+        _ => {
+            client.write_all(&[0x00, 0x5B, 0, 0, 0, 0, 0, 0]).await?;
+            Err(SocksError::UnsupportedCommand)
+        }
+    }
+}
+
+/// SOCKS4/4a CONNECT: same dial/relay path as SOCKS5, but replies in the
+/// SOCKS4 format (`VN CD DSTPORT DSTIP`, where `VN` is always `0x00`).
+async fn handle_connect_socks4(
+    mut client: TcpStream,
+    target_addr: &str,
+    port: u16,
+    upstream: Option<&UpstreamConfig>,
+    transfer: &TransferConfig,
+) -> Result<(), SocksError> {
+    match dial_target(target_addr, port, upstream).await {
+        Ok(target) => {
+            client.write_all(&[0x00, 0x5A, 0, 0, 0, 0, 0, 0]).await?;
+
+            let started = Instant::now();
+            let transferred = relay(client, target, transfer).await;
+            log_transfer_summary("SOCKS4 CONNECT", target_addr, port, &transferred, started.elapsed());
+            transferred.map(|_| ())
+        }
+        Err(e) => {
+            client.write_all(&[0x00, 0x5B, 0, 0, 0, 0, 0, 0]).await?;
+            Err(e)
         }
     }
 }
 
-fn handle_bind(client: &mut TcpStream, target_addr: &str, port: u16) -> Result<(), SocksError> {
+/// Reads a NUL-terminated string (used for the SOCKS4 USERID and SOCKS4a
+/// hostname fields), consuming the terminator.
+async fn read_cstring(stream: &mut TcpStream) -> Result<String, SocksError> {
+    let mut bytes = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Dials `target_addr:port` through an upstream SOCKS5 proxy (e.g. a local
+/// Tor daemon) instead of connecting directly, carrying the original domain
+/// name in the CONNECT request so the upstream resolves it (this is what
+/// lets `.onion` names work at all).
+async fn connect_via_upstream(upstream_addr: SocketAddr, target_addr: &str, port: u16) -> Result<TcpStream, SocksError> {
+    let mut upstream = TcpStream::connect(upstream_addr).await?;
+
+    // Method negotiation: offer no-auth only.
+    upstream.write_all(&[SOCKS_VERSION, 0x01, METHOD_NO_AUTH]).await?;
+    let mut method_reply = [0u8; 2];
+    upstream.read_exact(&mut method_reply).await?;
+    if method_reply[0] != SOCKS_VERSION || method_reply[1] != METHOD_NO_AUTH {
+        return Err(SocksError::UpstreamError("upstream rejected no-auth method".into()));
+    }
+
+    // CONNECT request using the domain-name address type so the upstream,
+    // not us, resolves `target_addr`.
+    if target_addr.len() > u8::MAX as usize {
+        return Err(SocksError::UpstreamError("target address too long".into()));
+    }
+    let mut request = vec![SOCKS_VERSION, 0x01, 0x00, 0x03, target_addr.len() as u8];
+    request.extend_from_slice(target_addr.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    upstream.write_all(&request).await?;
+
+    let mut reply_header = [0u8; 4];
+    upstream.read_exact(&mut reply_header).await?;
+    if reply_header[1] != 0x00 {
+        return Err(SocksError::UpstreamError(format!("upstream refused CONNECT (reply code {})", reply_header[1])));
+    }
+    skip_bound_address(&mut upstream, reply_header[3]).await?;
+
+    Ok(upstream)
+}
+
+/// Reads and discards the BND.ADDR/BND.PORT fields of a SOCKS5 reply; we
+/// only need the stream positioned past them, not their value.
+async fn skip_bound_address(stream: &mut TcpStream, address_type: u8) -> Result<(), SocksError> {
+    let addr_len = match address_type {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(SocksError::UpstreamError("unsupported bound address type".into())),
+    };
+    let mut discard = vec![0u8; addr_len + 2]; // address followed by the 2-byte port
+    stream.read_exact(&mut discard).await?;
+    Ok(())
+}
+
+async fn handle_bind(mut client: TcpStream, _target_addr: &str, _port: u16) -> Result<(), SocksError> {
     // Create a listener for incoming connections
-    let listener = TcpListener::bind("0.0.0.0:0")?;
+    let listener = TcpListener::bind("0.0.0.0:0").await?;
     let bind_addr = listener.local_addr()?;
-    
+
     // Send first reply with bound address
-    let response = [
-        SOCKS_VERSION, 0x00, 0x00, 0x01,
-        // Convert bind_addr IP and port to bytes
-        0, 0, 0, 0, // Replace with actual bound IP
-        (bind_addr.port() >> 8) as u8, bind_addr.port() as u8,
-    ];
-    client.write_all(&response)?;
-    
+    let response = build_socks_reply(0x00, bind_addr);
+    client.write_all(&response).await?;
+
     // Wait for incoming connection
-    if let Ok((target, _)) = listener.accept() {
+    if let Ok((_target, _)) = listener.accept().await {
         // Send second reply confirming connection
-        client.write_all(&response)?;
+        client.write_all(&response).await?;
         // Handle data transfer like in CONNECT
         // ... similar to handle_connect's forwarding logic
     }
     Ok(())
 }
 
-fn handle_udp(client: &mut TcpStream, _target_addr: &str, _port: u16) -> Result<(), SocksError> {
+async fn handle_udp(mut client: TcpStream, _target_addr: &str, _port: u16) -> Result<(), SocksError> {
     // Create UDP socket
-    let udp_socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
     let bind_addr = udp_socket.local_addr()?;
-    
-    // Send reply with UDP server address
-    let response = [
-        SOCKS_VERSION, 0x00, 0x00, 0x01,
-        0, 0, 0, 0, // Replace with actual UDP server IP
-        (bind_addr.port() >> 8) as u8, bind_addr.port() as u8,
-    ];
-    client.write_all(&response)?;
-    
-    // Handle UDP forwarding in a separate thread
-    // ... UDP relay logic would go here
-    
-    Ok(())
+
+    // Send reply with the real bound UDP server address
+    client.write_all(&build_socks_reply(0x00, bind_addr)).await?;
+
+    // Per the SOCKS5 spec, the UDP association is torn down once the
+    // controlling TCP connection closes. Race the relay loop against that
+    // connection reaching EOF (or failing), instead of polling a shared flag.
+    let mut control_buf = [0u8; 1];
+    let watch_control = async {
+        while matches!(client.read(&mut control_buf).await, Ok(n) if n > 0) {}
+    };
+
+    tokio::select! {
+        _ = watch_control => Ok(()),
+        result = udp_relay_loop(&udp_socket) => result,
+    }
 }
 
-fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:1080")?;
-    println!("SOCKS5 proxy listening on 0.0.0.0:1080");
-    
-    for stream in listener.incoming() {
-        match stream {
-            Ok(client) => {
-                println!("New connection from: {}", client.peer_addr()?);
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(client) {
-                        eprintln!("Client error: {:?}", e);
+/// Relays SOCKS5 UDP ASSOCIATE datagrams for as long as the caller keeps
+/// polling this future (the controlling TCP connection's lifetime, via
+/// `tokio::select!` in `handle_udp`).
+///
+/// The first datagram received is assumed to come from the SOCKS client and
+/// fixes its source address for the lifetime of the association; any later
+/// datagram from that address is client -> target traffic, while datagrams
+/// from addresses we've previously forwarded to are treated as target ->
+/// client replies and re-wrapped with the SOCKS5 UDP header.
+async fn udp_relay_loop(udp_socket: &UdpSocket) -> Result<(), SocksError> {
+    let mut buf = [0u8; 65536];
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut known_targets: HashSet<SocketAddr> = HashSet::new();
+
+    loop {
+        let (n, from) = udp_socket.recv_from(&mut buf).await?;
+        let datagram = buf[..n].to_vec();
+
+        if client_addr.is_none_or(|addr| addr == from) {
+            client_addr = Some(from);
+
+            let Some((frag, target_host, target_port, header_len)) = parse_udp_header(&datagram) else {
+                continue;
+            };
+            if frag != 0 {
+                continue; // fragmentation is not supported; drop per spec guidance
+            }
+            let Ok(mut resolved) = tokio::net::lookup_host((target_host.as_str(), target_port)).await else {
+                continue;
+            };
+            let Some(target) = resolved.next() else {
+                continue;
+            };
+
+            known_targets.insert(target);
+            udp_socket.send_to(&datagram[header_len..], target).await.ok();
+        } else if known_targets.contains(&from) {
+            if let Some(client) = client_addr {
+                let mut wrapped = Vec::with_capacity(datagram.len() + 22);
+                wrapped.extend_from_slice(&[0x00, 0x00, 0x00]); // RSV, RSV, FRAG
+                match from {
+                    SocketAddr::V4(v4) => {
+                        wrapped.push(0x01);
+                        wrapped.extend_from_slice(&v4.ip().octets());
                     }
-                });
+                    SocketAddr::V6(v6) => {
+                        wrapped.push(0x04);
+                        wrapped.extend_from_slice(&v6.ip().octets());
+                    }
+                }
+                wrapped.extend_from_slice(&from.port().to_be_bytes());
+                wrapped.extend_from_slice(&datagram);
+                udp_socket.send_to(&wrapped, client).await.ok();
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
         }
+        // Anything else is neither the client nor a known target; drop it.
     }
-    
-    Ok(())
-}
\ No newline at end of file
+}
+
+/// Parses a SOCKS5 UDP request header: 2 reserved bytes, FRAG, ATYP, address
+/// and port. Returns `(frag, target_host, target_port, header_len)`, where
+/// `header_len` is the offset at which the forwarded payload begins.
+fn parse_udp_header(data: &[u8]) -> Option<(u8, String, u16, usize)> {
+    if data.len() < 4 {
+        return None;
+    }
+    let frag = data[2];
+    let address_type = data[3];
+    let mut offset = 4;
+
+    let target_host = match address_type {
+        0x01 => {
+            if data.len() < offset + 4 {
+                return None;
+            }
+            let addr = &data[offset..offset + 4];
+            offset += 4;
+            format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3])
+        }
+        0x03 => {
+            let len = *data.get(offset)? as usize;
+            offset += 1;
+            if data.len() < offset + len {
+                return None;
+            }
+            let domain = String::from_utf8_lossy(&data[offset..offset + len]).to_string();
+            offset += len;
+            domain
+        }
+        0x04 => {
+            if data.len() < offset + 16 {
+                return None;
+            }
+            let addr = &data[offset..offset + 16];
+            offset += 16;
+            format!("[{}]", (0..8).map(|i| format!("{:02x}{:02x}", addr[i * 2], addr[i * 2 + 1]))
+                .collect::<Vec<String>>().join(":"))
+        }
+        _ => return None,
+    };
+
+    if data.len() < offset + 2 {
+        return None;
+    }
+    let port = u16::from_be_bytes([data[offset], data[offset + 1]]);
+    offset += 2;
+
+    Some((frag, target_host, port, offset))
+}
+
+/// Builds a SOCKS5 reply (method reply code + bound address) using the
+/// real address family and octets of `addr`, rather than a hardcoded
+/// `0.0.0.0`.
+fn build_socks_reply(reply_code: u8, addr: SocketAddr) -> Vec<u8> {
+    let mut response = vec![SOCKS_VERSION, reply_code, 0x00];
+    match addr {
+        SocketAddr::V4(v4) => {
+            response.push(0x01);
+            response.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            response.push(0x04);
+            response.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    response.extend_from_slice(&addr.port().to_be_bytes());
+    response
+}
+
+/// Builds the configured `Authenticator`, or `None` to run in no-auth mode.
+///
+/// Reads `SOCKS_USER` / `SOCKS_PASS` from the environment; set both to
+/// require RFC 1929 username/password authentication, leave either unset
+/// to fall back to no-auth.
+fn configured_authenticator() -> Option<Arc<dyn Authenticator>> {
+    let user = std::env::var("SOCKS_USER").ok()?;
+    let pass = std::env::var("SOCKS_PASS").ok()?;
+    let mut users = HashMap::new();
+    users.insert(user, pass);
+    Some(Arc::new(StaticCredentials::new(users)))
+}
+
+/// Builds the configured upstream chaining proxy, or `None` to always dial
+/// targets directly.
+///
+/// Reads `SOCKS_UPSTREAM` (a `host:port`, typically Tor's local SOCKS port)
+/// from the environment; `SOCKS_UPSTREAM_ALWAYS=1` chains every CONNECT
+/// through it instead of just `.onion` targets.
+fn configured_upstream() -> Option<Arc<UpstreamConfig>> {
+    let addr = std::env::var("SOCKS_UPSTREAM").ok()?.parse().ok()?;
+    let always_chain = std::env::var("SOCKS_UPSTREAM_ALWAYS").as_deref() == Ok("1");
+    Some(Arc::new(UpstreamConfig { addr, always_chain }))
+}
+
+/// Builds the bandwidth accounting and idle-timeout settings for the
+/// CONNECT forwarding loop.
+///
+/// `SOCKS_IDLE_TIMEOUT_SECS` overrides the default 300s idle timeout.
+/// `SOCKS_MAX_BYTES_PER_CONN` / `SOCKS_MAX_BYTES_TOTAL` set optional
+/// per-connection and proxy-wide byte caps.
+fn configured_transfer() -> TransferConfig {
+    let idle_timeout = std::env::var("SOCKS_IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(300));
+    let max_bytes_per_connection = std::env::var("SOCKS_MAX_BYTES_PER_CONN").ok().and_then(|v| v.parse().ok());
+    let global_cap = std::env::var("SOCKS_MAX_BYTES_TOTAL").ok().and_then(|v| v.parse().ok());
+
+    TransferConfig {
+        idle_timeout,
+        max_bytes_per_connection,
+        global_cap,
+        global_used: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let listener = TcpListener::bind("0.0.0.0:1080").await?;
+    println!("SOCKS5 proxy listening on 0.0.0.0:1080");
+
+    let authenticator = configured_authenticator();
+    if authenticator.is_some() {
+        println!("Username/password authentication enabled");
+    }
+
+    let upstream = configured_upstream();
+    if let Some(cfg) = &upstream {
+        println!("Chaining through upstream SOCKS5 proxy at {} (always_chain={})", cfg.addr, cfg.always_chain);
+    }
+
+    let transfer = configured_transfer();
+
+    loop {
+        let (client, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Connection failed: {}", e);
+                continue;
+            }
+        };
+        println!("New connection from: {}", addr);
+
+        let authenticator = authenticator.clone();
+        let upstream = upstream.clone();
+        let transfer = transfer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(client, authenticator, upstream, transfer).await {
+                eprintln!("Client error: {}", e);
+            }
+        });
+    }
+}